@@ -0,0 +1,515 @@
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::marker::PhantomData;
+use std::net::UdpSocket;
+use std::fmt;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use time::{Duration, SteadyTime};
+use epoll;
+
+use super::crypto::{self, Crypto};
+use super::signal;
+
+pub type NetworkId = u64;
+pub type Options = u8;
+
+const PROTOCOL_VERSION: u8 = 1;
+const DEFAULT_OPTIONS: Options = PROTOCOL_VERSION;
+
+#[derive(Debug)]
+pub enum Error {
+    ParseError(&'static str),
+    UnknownNetwork(NetworkId),
+    SocketError(&'static str),
+    DeviceError(&'static str),
+}
+
+
+/// A raw packet source/sink -- a tun or tap file descriptor -- that `Cloud::run` multiplexes
+/// alongside the UDP socket.
+pub trait VirtualInterface: AsRawFd {
+    fn ifname(&self) -> &str;
+    fn read_packet(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+/// A packet that carries enough addressing information for a `Table` to learn and route on, and
+/// that knows how to encode/decode itself on the wire.
+pub trait InterfaceMessage: Sized + Clone {
+    type Addr: Copy;
+    fn src(&self) -> Self::Addr;
+    fn dst(&self) -> Self::Addr;
+    fn encode_to(&self, buffer: &mut [u8]) -> usize;
+    fn parse_from(data: &[u8]) -> Result<Self, Error>;
+}
+
+/// A forwarding table mapping addresses of type `Addr` to the peer that packets for them are sent
+/// to. `MacTable` and `RoutingTable` are both `Table`s, driven by the same `Cloud` engine:
+/// `MacTable` learns from the source addresses of received packets, while `RoutingTable` ignores
+/// `learn` and only holds routes added with `add_route`.
+pub trait Table {
+    type Addr: Copy;
+    fn learn(&mut self, addr: Self::Addr, peer: SocketAddr);
+    fn lookup(&self, addr: &Self::Addr) -> Option<SocketAddr>;
+    fn housekeep(&mut self);
+}
+
+
+/// Tracks the current peer set (for `contains`/broadcast) and, separately, the order in which
+/// peers expire. `expirations` is pushed to in non-decreasing order (timeout is constant, so
+/// `now() + timeout` only grows), so `timeout()` only ever looks at entries that have actually
+/// expired instead of scanning every peer. A peer that's re-added before it expires leaves its
+/// old `expirations` entry in place; `timeout()` recognises it as stale by checking whether it
+/// still matches the peer's current expiry in `peers` before removing anything.
+struct PeerList {
+    timeout: Duration,
+    peers: HashMap<SocketAddr, SteadyTime>,
+    expirations: VecDeque<(SteadyTime, SocketAddr)>
+}
+
+impl PeerList {
+    fn new(timeout: Duration) -> PeerList {
+        PeerList{peers: HashMap::new(), timeout: timeout, expirations: VecDeque::new()}
+    }
+
+    fn timeout(&mut self) {
+        let now = SteadyTime::now();
+        while let Some(&(expiry, addr)) = self.expirations.front() {
+            if expiry > now {
+                break;
+            }
+            self.expirations.pop_front();
+            if self.peers.get(&addr) == Some(&expiry) {
+                debug!("Forgot peer: {:?}", addr);
+                self.peers.remove(&addr);
+            }
+        }
+    }
+
+    fn contains(&mut self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    fn add(&mut self, addr: &SocketAddr) {
+        let expiry = SteadyTime::now() + self.timeout;
+        if self.peers.insert(*addr, expiry).is_none() {
+            info!("New peer: {:?}", addr);
+        }
+        self.expirations.push_back((expiry, *addr));
+    }
+
+    fn addrs(&self) -> hash_map::Keys<SocketAddr, SteadyTime> {
+        self.peers.keys()
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        if self.peers.remove(&addr).is_some() {
+            info!("Removed peer: {:?}", addr);
+        }
+    }
+}
+
+
+enum Envelope<M> {
+    Data(M),
+    Peers(Vec<SocketAddr>),
+    GetPeers,
+    Close
+}
+
+const MESSAGE_TYPE_DATA: u8 = 0;
+const MESSAGE_TYPE_PEERS: u8 = 1;
+const MESSAGE_TYPE_GET_PEERS: u8 = 2;
+const MESSAGE_TYPE_CLOSE: u8 = 3;
+
+// Header layout: network id (8 bytes), options/version (1 byte), message type (1 byte), body.
+// `options` isn't interpreted yet, but carrying it from the start means future capability
+// negotiation doesn't require another wire format break.
+const HEADER_LEN: usize = 10;
+
+fn encode<M: InterfaceMessage>(network: NetworkId, options: Options, msg: &Envelope<M>, buf: &mut [u8]) -> usize {
+    for i in 0..8 {
+        buf[i] = ((network >> (8*(7-i))) & 0xff) as u8;
+    }
+    buf[8] = options;
+    let mut pos = HEADER_LEN;
+    match *msg {
+        Envelope::Data(ref data) => {
+            buf[9] = MESSAGE_TYPE_DATA;
+            pos += data.encode_to(&mut buf[pos..]);
+        },
+        Envelope::Peers(ref peers) => {
+            buf[9] = MESSAGE_TYPE_PEERS;
+            for peer in peers {
+                let text = format!("{}", peer);
+                buf[pos] = text.len() as u8;
+                pos += 1;
+                buf[pos..pos+text.len()].copy_from_slice(text.as_bytes());
+                pos += text.len();
+            }
+        },
+        Envelope::GetPeers => buf[9] = MESSAGE_TYPE_GET_PEERS,
+        Envelope::Close => buf[9] = MESSAGE_TYPE_CLOSE
+    }
+    pos
+}
+
+fn decode<M: InterfaceMessage>(data: &[u8]) -> Result<(NetworkId, Options, Envelope<M>), Error> {
+    if data.len() < HEADER_LEN {
+        return Err(Error::ParseError("Truncated header"));
+    }
+    let mut network: NetworkId = 0;
+    for i in 0..8 {
+        network = (network << 8) | data[i] as NetworkId;
+    }
+    let options = data[8];
+    let mut pos = HEADER_LEN;
+    let msg = match data[9] {
+        MESSAGE_TYPE_DATA => Envelope::Data(try!(M::parse_from(&data[pos..]))),
+        MESSAGE_TYPE_PEERS => {
+            let mut peers = Vec::new();
+            while pos < data.len() {
+                let len = data[pos] as usize;
+                pos += 1;
+                if pos + len > data.len() {
+                    return Err(Error::ParseError("Truncated peer entry"));
+                }
+                let text = match ::std::str::from_utf8(&data[pos..pos+len]) {
+                    Ok(text) => text,
+                    Err(_) => return Err(Error::ParseError("Invalid peer address"))
+                };
+                let addr = match text.parse() {
+                    Ok(addr) => addr,
+                    Err(_) => return Err(Error::ParseError("Invalid peer address"))
+                };
+                peers.push(addr);
+                pos += len;
+            }
+            Envelope::Peers(peers)
+        },
+        MESSAGE_TYPE_GET_PEERS => Envelope::GetPeers,
+        MESSAGE_TYPE_CLOSE => Envelope::Close,
+        _ => return Err(Error::ParseError("Unknown message type"))
+    };
+    Ok((network, options, msg))
+}
+
+/// The actual send implementation, taking its own pieces of `Cloud` by reference instead of
+/// `&mut self` so callers can use it while still holding a borrow into another field of `Cloud`
+/// (see `Cloud::handle_packet`'s broadcast path).
+fn send_packet<M: InterfaceMessage, A: ToSocketAddrs + fmt::Display>(socket: &UdpSocket, crypto: &mut Option<Crypto>, buffer_out: &mut [u8], network: NetworkId, addr: A, msg: &Envelope<M>) -> Result<(), Error> {
+    debug!("Sending message on network {} to {}", network, addr);
+    let size = encode(network, DEFAULT_OPTIONS, msg, buffer_out);
+    let packet_size = match *crypto {
+        Some(ref mut crypto) => {
+            let mut plaintext = buffer_out[..size].to_vec();
+            let header = crypto.seal(&mut plaintext);
+            buffer_out[..crypto::HEADER_LEN].copy_from_slice(&header);
+            buffer_out[crypto::HEADER_LEN..crypto::HEADER_LEN+plaintext.len()].copy_from_slice(&plaintext);
+            crypto::HEADER_LEN + plaintext.len()
+        },
+        None => size
+    };
+    match socket.send_to(&buffer_out[..packet_size], addr) {
+        Ok(written) if written == packet_size => Ok(()),
+        Ok(_) => Err(Error::SocketError("Sent out truncated packet")),
+        Err(e) => {
+            error!("Failed to send via network {:?}", e);
+            Err(Error::SocketError("IOError when sending"))
+        }
+    }
+}
+
+
+/// One isolated overlay: its own virtual interface, address table and peer list. Several of
+/// these can share a single `Cloud`'s UDP socket, distinguished on the wire by `id`.
+struct Network<Tbl, Iface> {
+    id: NetworkId,
+    iface: Iface,
+    table: Tbl,
+    peers: PeerList,
+    next_peerlist: SteadyTime,
+}
+
+/// The shared engine behind both the layer-2 switch (`EthCloud`) and the layer-3 router
+/// (`IpCloud`): one UDP socket carrying any number of independent networks, generic over what
+/// kind of packet and table each network deals in.
+pub struct Cloud<M: InterfaceMessage, Tbl: Table<Addr=M::Addr>, Iface: VirtualInterface> {
+    networks: Vec<Network<Tbl, Iface>>,
+    socket: UdpSocket,
+    crypto: Option<Crypto>,
+    update_freq: Duration,
+    buffer_out: [u8; 64*1024],
+    last_housekeep: SteadyTime,
+    _message: PhantomData<M>,
+}
+
+impl<M: InterfaceMessage, Tbl: Table<Addr=M::Addr>, Iface: VirtualInterface> Cloud<M, Tbl, Iface> {
+    pub fn new(listen: String, peer_timeout: Duration, networks: Vec<(NetworkId, Iface, Tbl)>, passphrase: Option<&str>) -> Self {
+        let socket = match UdpSocket::bind(&listen as &str) {
+            Ok(socket) => socket,
+            _ => panic!("Failed to open socket")
+        };
+        let now = SteadyTime::now();
+        let networks = networks.into_iter().map(|(id, iface, table)| Network{
+            id: id,
+            iface: iface,
+            table: table,
+            peers: PeerList::new(peer_timeout),
+            next_peerlist: now,
+        }).collect();
+        Cloud{
+            networks: networks,
+            socket: socket,
+            crypto: passphrase.map(Crypto::new),
+            update_freq: peer_timeout/2,
+            buffer_out: [0; 64*1024],
+            last_housekeep: now,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn table_mut(&mut self, id: NetworkId) -> Option<&mut Tbl> {
+        self.networks.iter_mut().find(|network| network.id == id).map(|network| &mut network.table)
+    }
+
+    fn send_msg<A: ToSocketAddrs + fmt::Display>(&mut self, network: NetworkId, addr: A, msg: &Envelope<M>) -> Result<(), Error> {
+        send_packet(&self.socket, &mut self.crypto, &mut self.buffer_out, network, addr, msg)
+    }
+
+    pub fn connect<A: ToSocketAddrs + fmt::Display>(&mut self, network: NetworkId, addr: A) -> Result<(), Error> {
+        info!("Connecting to {} on network {}", addr, network);
+        self.send_msg(network, addr, &Envelope::GetPeers)
+    }
+
+    fn housekeep(&mut self) -> Result<(), Error> {
+        debug!("Running housekeeping...");
+        let now = SteadyTime::now();
+        for network in &mut self.networks {
+            network.peers.timeout();
+            network.table.housekeep();
+        }
+        for i in 0..self.networks.len() {
+            if self.networks[i].next_peerlist > now {
+                continue;
+            }
+            debug!("Send peer list to all peers on network {}", self.networks[i].id);
+            let id = self.networks[i].id;
+            let peers: Vec<SocketAddr> = self.networks[i].peers.addrs().cloned().collect();
+            let msg = Envelope::Peers(peers.clone());
+            for addr in &peers {
+                try!(self.send_msg(id, addr, &msg));
+            }
+            self.networks[i].next_peerlist = now + self.update_freq;
+        }
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, network_idx: usize, data: &[u8]) -> Result<(), Error> {
+        let packet = try!(M::parse_from(data));
+        let id = self.networks[network_idx].id;
+        let dest = self.networks[network_idx].table.lookup(&packet.dst());
+        match dest {
+            Some(addr) => {
+                debug!("Found destination for network {}, sending to {}", id, addr);
+                try!(self.send_msg(id, addr, &Envelope::Data(packet)))
+            },
+            None => {
+                // Send directly to each peer address borrowed from the live peer set instead of
+                // going through `self.send_msg` (which would need the whole of `self`, including
+                // the peer set we're iterating): this is the hot path, run once per frame on
+                // every cache miss, so it must not allocate a fresh peer list each time.
+                debug!("No destination found on network {}, broadcasting", id);
+                for &addr in self.networks[network_idx].peers.addrs() {
+                    try!(send_packet(&self.socket, &mut self.crypto, &mut self.buffer_out, id, addr, &Envelope::Data(packet.clone())));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies and decrypts an incoming packet when a passphrase is configured, otherwise
+    /// decodes it as sent. Kept separate from `handle_net_message` so the `&mut self` borrow
+    /// needed to decrypt is released before that borrow is needed again to act on the message.
+    fn decode_packet(&mut self, src: SocketAddr, data: &[u8]) -> Result<(NetworkId, Options, Envelope<M>), Error> {
+        match self.crypto {
+            Some(ref mut crypto) => {
+                if data.len() < crypto::HEADER_LEN {
+                    return Err(Error::ParseError("Truncated packet"));
+                }
+                let (header, ciphertext) = data.split_at(crypto::HEADER_LEN);
+                let mut ciphertext = ciphertext.to_vec();
+                match crypto.open(src, header, &mut ciphertext) {
+                    Ok(plain_len) => decode(&ciphertext[..plain_len]),
+                    Err(e) => {
+                        debug!("Dropping packet from {}: {:?}", src, e);
+                        Err(Error::ParseError("Failed to decrypt packet"))
+                    }
+                }
+            },
+            None => decode(data)
+        }
+    }
+
+    fn handle_net_message(&mut self, peer: SocketAddr, network: NetworkId, _options: Options, msg: Envelope<M>) -> Result<(), Error> {
+        let idx = match self.networks.iter().position(|n| n.id == network) {
+            Some(idx) => idx,
+            None => {
+                info!("Ignoring message from {} for unknown network {}", peer, network);
+                return Err(Error::UnknownNetwork(network));
+            }
+        };
+        match msg {
+            Envelope::Data(packet) => {
+                let mut buf = [0; 64*1024];
+                let size = packet.encode_to(&mut buf);
+                match self.networks[idx].iface.write_packet(&buf[..size]) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        error!("Failed to send via virtual interface {:?}", e);
+                        return Err(Error::DeviceError("Failed to write to virtual interface"));
+                    }
+                }
+                self.networks[idx].peers.add(&peer);
+                self.networks[idx].table.learn(packet.src(), peer);
+            },
+            Envelope::Peers(peers) => {
+                self.networks[idx].peers.add(&peer);
+                for p in &peers {
+                    if ! self.networks[idx].peers.contains(p) {
+                        try!(self.connect(network, p));
+                    }
+                }
+            },
+            Envelope::GetPeers => {
+                self.networks[idx].peers.add(&peer);
+                let peers: Vec<SocketAddr> = self.networks[idx].peers.addrs().cloned().collect();
+                try!(self.send_msg(network, peer, &Envelope::Peers(peers)));
+            },
+            Envelope::Close => {
+                self.networks[idx].peers.remove(&peer);
+                // `crypto`'s replay filters are keyed by `SocketAddr` alone, but the same peer
+                // address can be joined to several networks at once: only drop its replay state
+                // once it has left all of them, otherwise a still-active network loses replay
+                // protection against traffic it already authenticated.
+                let still_present = self.networks.iter_mut().any(|n| n.peers.contains(&peer));
+                if !still_present {
+                    if let Some(ref mut crypto) = self.crypto {
+                        crypto.forget_peer(&peer);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        info!("Closing all connections and shutting down");
+        for i in 0..self.networks.len() {
+            let id = self.networks[i].id;
+            let peers: Vec<SocketAddr> = self.networks[i].peers.addrs().cloned().collect();
+            for addr in &peers {
+                match self.send_msg(id, addr, &Envelope::Close) {
+                    Ok(_) => (),
+                    Err(e) => error!("Error: {:?}", e)
+                }
+            }
+        }
+    }
+
+    pub fn run(&mut self) {
+        signal::trap_shutdown_signals();
+        let epoll_handle = epoll::create1(0).expect("Failed to create epoll handle");
+        let socket_fd = self.socket.as_raw_fd();
+        let mut socket_event = epoll::EpollEvent{events: epoll::util::event_type::EPOLLIN, data: 0};
+        epoll::ctl(epoll_handle, epoll::util::ctl_op::ADD, socket_fd, &mut socket_event).expect("Failed to add socket to epoll handle");
+        for (i, network) in self.networks.iter().enumerate() {
+            let fd = network.iface.as_raw_fd();
+            let mut event = epoll::EpollEvent{events: epoll::util::event_type::EPOLLIN, data: (i+1) as u64};
+            epoll::ctl(epoll_handle, epoll::util::ctl_op::ADD, fd, &mut event).expect("Failed to add virtual interface to epoll handle");
+        }
+        let mut events = vec![epoll::EpollEvent{events: 0, data: 0}; self.networks.len()+1];
+        let mut buffer = [0; 64*1024];
+        loop {
+            let count = epoll::wait(epoll_handle, &mut events, 1000).expect("Epoll wait failed");
+            // Process events
+            for i in 0..count {
+                let data = events[i as usize].data;
+                if data == 0 {
+                    match self.socket.recv_from(&mut buffer) {
+                        Ok((size, src)) => {
+                            match self.decode_packet(src, &buffer[..size]).and_then(|(network, options, msg)| self.handle_net_message(src, network, options, msg)) {
+                                Ok(_) => (),
+                                Err(e) => error!("Error: {:?}", e)
+                            }
+                        },
+                        Err(_error) => panic!("Failed to read from network socket")
+                    }
+                } else {
+                    let idx = (data - 1) as usize;
+                    match self.networks[idx].iface.read_packet(&mut buffer) {
+                        Ok(size) => {
+                            match self.handle_packet(idx, &buffer[..size]) {
+                                Ok(_) => (),
+                                Err(e) => error!("Error: {:?}", e)
+                            }
+                        },
+                        Err(_error) => panic!("Failed to read from virtual interface")
+                    }
+                }
+            }
+            // Do the housekeeping
+            if self.last_housekeep < SteadyTime::now() + Duration::seconds(1) {
+                match self.housekeep() {
+                    Ok(_) => (),
+                    Err(e) => error!("Error: {:?}", e)
+                }
+                self.last_housekeep = SteadyTime::now()
+            }
+            if signal::shutdown_requested() {
+                info!("Received shutdown signal");
+                break;
+            }
+        }
+        self.close();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn timeout_evicts_peer_once_truly_expired() {
+        let mut peers = PeerList::new(Duration::milliseconds(20));
+        let p = addr(1);
+        peers.add(&p);
+        ::std::thread::sleep(::std::time::Duration::from_millis(40));
+        peers.timeout();
+        assert!(!peers.contains(&p));
+    }
+
+    /// Regression test for the `expirations` staleness invariant described on `PeerList`: a peer
+    /// re-added before its original expiry must survive `timeout()` once that original expiry
+    /// passes -- only the now-stale `expirations` entry should be discarded, not the peer itself.
+    #[test]
+    fn readd_before_expiry_survives_past_the_original_expiry() {
+        let mut peers = PeerList::new(Duration::milliseconds(80));
+        let p = addr(2);
+        peers.add(&p);
+        ::std::thread::sleep(::std::time::Duration::from_millis(40));
+        peers.add(&p);
+        // Past the original add's expiry, but not the re-add's.
+        ::std::thread::sleep(::std::time::Duration::from_millis(50));
+        peers.timeout();
+        assert!(peers.contains(&p));
+    }
+}
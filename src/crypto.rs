@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use ring::aead;
+use ring::digest;
+use rand::{Rng, OsRng};
+
+pub const KEY_LEN: usize = 32;
+pub const SALT_LEN: usize = 16;
+pub const NONCE_PREFIX_LEN: usize = 4;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+pub const HEADER_LEN: usize = SALT_LEN + NONCE_PREFIX_LEN + 8;
+
+const KDF_ROUNDS: u32 = 10_000;
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+pub type Salt = [u8; SALT_LEN];
+
+/// Derives a 256 bit key from a passphrase and a per-instance salt by hashing the two together
+/// and then re-hashing that digest `KDF_ROUNDS` times. Not a substitute for a proper password KDF, but enough to keep the shared
+/// passphrase itself off the wire.
+fn derive_key(passphrase: &str, salt: &Salt) -> [u8; KEY_LEN] {
+    let mut material = Vec::with_capacity(passphrase.len() + salt.len());
+    material.extend_from_slice(passphrase.as_bytes());
+    material.extend_from_slice(salt);
+    let mut hashed = digest::digest(&digest::SHA256, &material).as_ref().to_vec();
+    for _ in 0..KDF_ROUNDS {
+        hashed = digest::digest(&digest::SHA256, &hashed).as_ref().to_vec();
+    }
+    let mut key = [0; KEY_LEN];
+    key.copy_from_slice(&hashed[..KEY_LEN]);
+    key
+}
+
+fn random_salt() -> Salt {
+    let mut salt = [0; SALT_LEN];
+    OsRng::new().expect("Failed to open random number generator").fill_bytes(&mut salt);
+    salt
+}
+
+fn build_nonce(prefix: &[u8], counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    for i in 0..8 {
+        nonce[NONCE_PREFIX_LEN + i] = ((counter >> (8 * (7 - i))) & 0xff) as u8;
+    }
+    nonce
+}
+
+/// Tracks which of the last `REPLAY_WINDOW_SIZE` counters seen from one peer have already been
+/// accepted, so a captured packet cannot be replayed.
+struct ReplayFilter {
+    highest: u64,
+    seen: u64
+}
+
+impl ReplayFilter {
+    fn new() -> ReplayFilter {
+        ReplayFilter{highest: 0, seen: 0}
+    }
+
+    /// Read-only check: would `counter` be accepted? Does not record anything, so a forged
+    /// packet that fails authentication afterwards leaves the filter untouched.
+    fn check(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            true
+        } else {
+            let diff = self.highest - counter;
+            if diff >= REPLAY_WINDOW_SIZE {
+                false
+            } else {
+                self.seen & (1u64 << diff) == 0
+            }
+        }
+    }
+
+    /// Records `counter` as seen. Must only be called after the packet it came from has been
+    /// authenticated, otherwise an attacker could bump `highest` with a forged counter and
+    /// permanently lock out genuine packets from the real peer.
+    fn commit(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            let diff = self.highest - counter;
+            self.seen |= 1u64 << diff;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    InvalidPacket,
+    AuthenticationFailed,
+    Replayed
+}
+
+/// Wraps message bodies in an AEAD cipher so that only peers who know the configured passphrase
+/// can read or inject traffic.
+///
+/// Each instance picks its own random salt and derives its sending key from
+/// `passphrase + salt`; that salt rides along in the cleartext header of every packet it sends
+/// so receivers can derive the matching opening key without a separate handshake message.
+/// Opening keys are cached per salt since the KDF is deliberately expensive.
+pub struct Crypto {
+    passphrase: String,
+    salt: Salt,
+    sealing_key: aead::SealingKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    send_counter: u64,
+    opening_keys: HashMap<Salt, aead::OpeningKey>,
+    replay_filters: HashMap<SocketAddr, ReplayFilter>
+}
+
+impl Crypto {
+    pub fn new(passphrase: &str) -> Crypto {
+        let salt = random_salt();
+        let key_bytes = derive_key(passphrase, &salt);
+        let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key_bytes).expect("Invalid key length");
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        OsRng::new().expect("Failed to open random number generator").fill_bytes(&mut nonce_prefix);
+        Crypto{
+            passphrase: passphrase.to_owned(),
+            salt: salt,
+            sealing_key: sealing_key,
+            nonce_prefix: nonce_prefix,
+            send_counter: 0,
+            opening_keys: HashMap::new(),
+            replay_filters: HashMap::new()
+        }
+    }
+
+    /// Encrypts `plaintext` in place and appends the auth tag. Returns the cleartext header
+    /// (salt + nonce) the caller must prepend to the packet so the receiver can decrypt it.
+    pub fn seal(&mut self, plaintext: &mut Vec<u8>) -> [u8; HEADER_LEN] {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+        let nonce = build_nonce(&self.nonce_prefix, counter);
+        plaintext.extend_from_slice(&[0; TAG_LEN]);
+        aead::seal_in_place(&self.sealing_key, &nonce, &[], plaintext, TAG_LEN).expect("Encryption failed");
+        let mut header = [0; HEADER_LEN];
+        header[..SALT_LEN].copy_from_slice(&self.salt);
+        header[SALT_LEN..].copy_from_slice(&nonce);
+        header
+    }
+
+    /// Verifies and decrypts `ciphertext` in place, rejecting packets whose nonce counter has
+    /// already been seen from this peer within the sliding replay window.
+    pub fn open(&mut self, peer: SocketAddr, header: &[u8], ciphertext: &mut [u8]) -> Result<usize, CryptoError> {
+        if header.len() != HEADER_LEN {
+            return Err(CryptoError::InvalidPacket);
+        }
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&header[..SALT_LEN]);
+        let nonce_bytes = &header[SALT_LEN..];
+        let mut counter = 0u64;
+        for &b in &nonce_bytes[NONCE_PREFIX_LEN..] {
+            counter = (counter << 8) | b as u64;
+        }
+        if !self.replay_filters.entry(peer).or_insert_with(ReplayFilter::new).check(counter) {
+            return Err(CryptoError::Replayed);
+        }
+        if !self.opening_keys.contains_key(&salt) {
+            let key_bytes = derive_key(&self.passphrase, &salt);
+            let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key_bytes).expect("Invalid key length");
+            self.opening_keys.insert(salt, opening_key);
+        }
+        let opening_key = &self.opening_keys[&salt];
+        match aead::open_in_place(opening_key, &nonce_bytes[..], &[], 0, ciphertext) {
+            Ok(plaintext) => {
+                let len = plaintext.len();
+                // Only now that the tag has verified do we record the counter: committing on an
+                // unauthenticated counter would let a forged packet with a bogus counter lock out
+                // all genuine future packets from this peer (see ReplayFilter::commit).
+                self.replay_filters.get_mut(&peer).expect("replay filter just inserted above").commit(counter);
+                Ok(len)
+            },
+            Err(_) => Err(CryptoError::AuthenticationFailed)
+        }
+    }
+
+    pub fn forget_peer(&mut self, peer: &SocketAddr) {
+        self.replay_filters.remove(peer);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:3210".parse().unwrap()
+    }
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let mut sender = Crypto::new("secret passphrase");
+        let mut receiver = Crypto::new("secret passphrase");
+        let mut plaintext = b"hello vpncloud".to_vec();
+        let original = plaintext.clone();
+        let header = sender.seal(&mut plaintext);
+        let len = receiver.open(peer(), &header, &mut plaintext).expect("should decrypt");
+        assert_eq!(&plaintext[..len], &original[..]);
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_to_open() {
+        let mut sender = Crypto::new("correct passphrase");
+        let mut receiver = Crypto::new("wrong passphrase");
+        let mut plaintext = b"hello vpncloud".to_vec();
+        let header = sender.seal(&mut plaintext);
+        assert!(receiver.open(peer(), &header, &mut plaintext).is_err());
+    }
+
+    /// Regression test for the replay-filter-poisoning bug: a forged packet with a bogus
+    /// (near-max) counter that fails authentication must not lock out the real peer's
+    /// subsequent genuine packets.
+    #[test]
+    fn forged_counter_does_not_lock_out_genuine_peer() {
+        let mut sender = Crypto::new("secret passphrase");
+        let mut receiver = Crypto::new("secret passphrase");
+
+        let mut forged = vec![0u8; 32];
+        let mut forged_header = [0u8; HEADER_LEN];
+        forged_header[..SALT_LEN].copy_from_slice(&sender.salt);
+        for i in 0..8 {
+            forged_header[SALT_LEN + NONCE_PREFIX_LEN + i] = 0xff;
+        }
+        assert!(receiver.open(peer(), &forged_header, &mut forged).is_err());
+
+        let mut plaintext = b"still here".to_vec();
+        let original = plaintext.clone();
+        let header = sender.seal(&mut plaintext);
+        let len = receiver.open(peer(), &header, &mut plaintext).expect("genuine packet must still decrypt");
+        assert_eq!(&plaintext[..len], &original[..]);
+    }
+
+    #[test]
+    fn replay_filter_rejects_repeated_counter_only_after_commit() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.check(5));
+        assert!(filter.check(5));
+        filter.commit(5);
+        assert!(!filter.check(5));
+        assert!(filter.check(6));
+    }
+}
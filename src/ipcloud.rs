@@ -0,0 +1,293 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::HashMap;
+use std::fmt;
+
+use time::Duration;
+
+use super::tundev::TunDevice;
+use super::cloud::{Cloud, Table, InterfaceMessage, VirtualInterface, NetworkId, Error};
+
+
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+pub enum IpAddress {
+    V4([u8; 4]),
+    V6([u8; 16])
+}
+
+impl IpAddress {
+    fn max_prefix_len(&self) -> u8 {
+        match *self {
+            IpAddress::V4(_) => 32,
+            IpAddress::V6(_) => 128
+        }
+    }
+
+    fn masked(&self, prefix_len: u8) -> IpAddress {
+        match *self {
+            IpAddress::V4(bytes) => IpAddress::V4(mask4(&bytes, prefix_len)),
+            IpAddress::V6(bytes) => IpAddress::V6(mask16(&bytes, prefix_len))
+        }
+    }
+}
+
+fn mask4(bytes: &[u8; 4], prefix_len: u8) -> [u8; 4] {
+    let mut out = [0; 4];
+    apply_mask(bytes, prefix_len, &mut out);
+    out
+}
+
+fn mask16(bytes: &[u8; 16], prefix_len: u8) -> [u8; 16] {
+    let mut out = [0; 16];
+    apply_mask(bytes, prefix_len, &mut out);
+    out
+}
+
+fn apply_mask(bytes: &[u8], prefix_len: u8, out: &mut [u8]) {
+    let full_bytes = (prefix_len / 8) as usize;
+    for i in 0..full_bytes.min(out.len()) {
+        out[i] = bytes[i];
+    }
+    let rem_bits = prefix_len % 8;
+    if rem_bits > 0 && full_bytes < out.len() {
+        let mask = !0u8 << (8 - rem_bits);
+        out[full_bytes] = bytes[full_bytes] & mask;
+    }
+}
+
+impl fmt::Debug for IpAddress {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            IpAddress::V4(bytes) => write!(formatter, "{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3]),
+            IpAddress::V6(bytes) => {
+                for (i, chunk) in bytes.chunks(2).enumerate() {
+                    if i > 0 {
+                        try!(write!(formatter, ":"));
+                    }
+                    try!(write!(formatter, "{:x}", ((chunk[0] as u16) << 8) | chunk[1] as u16));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn parse_addresses(data: &[u8]) -> Result<(IpAddress, IpAddress), Error> {
+    if data.is_empty() {
+        return Err(Error::ParseError("Empty packet"));
+    }
+    match data[0] >> 4 {
+        4 => {
+            if data.len() < 20 {
+                return Err(Error::ParseError("Truncated ipv4 packet"));
+            }
+            let mut src = [0; 4];
+            let mut dst = [0; 4];
+            src.copy_from_slice(&data[12..16]);
+            dst.copy_from_slice(&data[16..20]);
+            Ok((IpAddress::V4(src), IpAddress::V4(dst)))
+        },
+        6 => {
+            if data.len() < 40 {
+                return Err(Error::ParseError("Truncated ipv6 packet"));
+            }
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(&data[8..24]);
+            dst.copy_from_slice(&data[24..40]);
+            Ok((IpAddress::V6(src), IpAddress::V6(dst)))
+        },
+        _ => Err(Error::ParseError("Unknown ip version"))
+    }
+}
+
+
+/// A raw ip packet as read from (or to be written to) the tun device. `src`/`dst` are parsed
+/// once up front so the routing table doesn't need to re-parse the header on every lookup.
+#[derive(Clone)]
+pub struct IpPacket {
+    data: Vec<u8>,
+    src: IpAddress,
+    dst: IpAddress
+}
+
+impl InterfaceMessage for IpPacket {
+    type Addr = IpAddress;
+
+    fn src(&self) -> IpAddress {
+        self.src
+    }
+
+    fn dst(&self) -> IpAddress {
+        self.dst
+    }
+
+    fn encode_to(&self, buffer: &mut [u8]) -> usize {
+        buffer[..self.data.len()].copy_from_slice(&self.data);
+        self.data.len()
+    }
+
+    fn parse_from(data: &[u8]) -> Result<Self, Error> {
+        let (src, dst) = try!(parse_addresses(data));
+        Ok(IpPacket{data: data.to_vec(), src: src, dst: dst})
+    }
+}
+
+impl VirtualInterface for TunDevice {
+    fn ifname(&self) -> &str {
+        self.ifname()
+    }
+
+    fn read_packet(&mut self, buffer: &mut [u8]) -> ::std::io::Result<usize> {
+        use std::io::Read;
+        self.read(buffer)
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> ::std::io::Result<()> {
+        self.write(data)
+    }
+}
+
+
+pub struct RoutingTable {
+    table: HashMap<(IpAddress, u8), SocketAddr>
+}
+
+impl RoutingTable {
+    pub fn new() -> RoutingTable {
+        RoutingTable{table: HashMap::new()}
+    }
+
+    /// Adds a static route for `address`/`prefix_len` pointing at the peer behind `addr`.
+    /// Unlike `MacTable`, routes are configured up front instead of learned from traffic.
+    pub fn add_route(&mut self, address: IpAddress, prefix_len: u8, addr: SocketAddr) {
+        let key = (address.masked(prefix_len), prefix_len);
+        if self.table.insert(key, addr).is_none() {
+            info!("New route: {:?}/{} => {}", address, prefix_len, addr);
+        }
+    }
+}
+
+impl Table for RoutingTable {
+    type Addr = IpAddress;
+
+    // Routes are static configuration, not learned from source addresses seen on the wire.
+    fn learn(&mut self, _addr: IpAddress, _peer: SocketAddr) {}
+
+    fn lookup(&self, addr: &IpAddress) -> Option<SocketAddr> {
+        let max_len = addr.max_prefix_len();
+        for prefix_len in (0..max_len+1).rev() {
+            let key = (addr.masked(prefix_len), prefix_len);
+            if let Some(&peer) = self.table.get(&key) {
+                return Some(peer);
+            }
+        }
+        None
+    }
+
+    fn housekeep(&mut self) {}
+}
+
+
+/// The layer-3 router: forwards ip packets between a tun device and peers using statically
+/// configured CIDR routes instead of learned mac addresses. A thin wrapper around the generic
+/// `Cloud` engine, mirroring `EthCloud`.
+pub struct IpCloud(Cloud<IpPacket, RoutingTable, TunDevice>);
+
+impl IpCloud {
+    pub fn new(networks: Vec<(NetworkId, &str)>, listen: String, peer_timeout: Duration, passphrase: Option<&str>) -> Self {
+        let networks = networks.into_iter().map(|(id, device)| {
+            let tundev = match TunDevice::new(device) {
+                Ok(tundev) => tundev,
+                _ => panic!("Failed to open tun device")
+            };
+            info!("Opened tun device {} for network {}", tundev.ifname(), id);
+            (id, tundev, RoutingTable::new())
+        }).collect();
+        IpCloud(Cloud::new(listen, peer_timeout, networks, passphrase))
+    }
+
+    pub fn add_route(&mut self, network: NetworkId, address: IpAddress, prefix_len: u8, addr: SocketAddr) -> Result<(), Error> {
+        match self.0.table_mut(network) {
+            Some(table) => {
+                table.add_route(address, prefix_len, addr);
+                Ok(())
+            },
+            None => {
+                error!("Cannot add route for unknown network {}", network);
+                Err(Error::UnknownNetwork(network))
+            }
+        }
+    }
+
+    pub fn connect<A: ToSocketAddrs + fmt::Display>(&mut self, network: NetworkId, addr: A) -> Result<(), Error> {
+        self.0.connect(network, addr)
+    }
+
+    pub fn run(&mut self) {
+        self.0.run()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddress {
+        IpAddress::V4([a, b, c, d])
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn mask4_on_byte_boundary() {
+        let addr = v4(192, 168, 1, 200);
+        assert_eq!(addr.masked(24), v4(192, 168, 1, 0));
+        assert_eq!(addr.masked(16), v4(192, 168, 0, 0));
+    }
+
+    #[test]
+    fn mask4_mid_byte_and_extremes() {
+        let addr = v4(192, 168, 1, 200);
+        // 200 = 0b1100_1000, a /25 keeps only the top bit of the last byte.
+        assert_eq!(addr.masked(25), v4(192, 168, 1, 128));
+        assert_eq!(addr.masked(32), addr);
+        assert_eq!(addr.masked(0), v4(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn mask6_on_byte_and_bit_boundaries() {
+        let addr = IpAddress::V6([0xff; 16]);
+        assert_eq!(addr.masked(128), addr);
+        assert_eq!(addr.masked(0), IpAddress::V6([0; 16]));
+        // First byte kept whole, second byte keeps only its top 4 bits.
+        assert_eq!(addr.masked(12), IpAddress::V6([0xff, 0xf0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn longest_prefix_match_prefers_more_specific_route() {
+        let mut table = RoutingTable::new();
+        let general = peer(1);
+        let specific = peer(2);
+        table.add_route(v4(10, 0, 0, 0), 8, general);
+        table.add_route(v4(10, 0, 1, 0), 24, specific);
+
+        assert_eq!(table.lookup(&v4(10, 0, 1, 42)), Some(specific));
+        assert_eq!(table.lookup(&v4(10, 0, 2, 42)), Some(general));
+        assert_eq!(table.lookup(&v4(11, 0, 0, 1)), None);
+    }
+
+    #[test]
+    fn exact_host_route_wins_over_default_route() {
+        let mut table = RoutingTable::new();
+        let default_peer = peer(1);
+        let host_peer = peer(2);
+        table.add_route(v4(0, 0, 0, 0), 0, default_peer);
+        table.add_route(v4(10, 0, 0, 5), 32, host_peer);
+
+        assert_eq!(table.lookup(&v4(10, 0, 0, 5)), Some(host_peer));
+        assert_eq!(table.lookup(&v4(10, 0, 0, 6)), Some(default_peer));
+    }
+}
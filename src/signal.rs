@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_shutdown_signal(_signum: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a handler for SIGINT/SIGTERM/SIGQUIT that just flags a shutdown request instead of
+/// terminating the process, so `EthCloud::run` gets a chance to tell its peers before exiting.
+pub fn trap_shutdown_signals() {
+    unsafe {
+        libc::signal(libc::SIGINT, handle_shutdown_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle_shutdown_signal as libc::sighandler_t);
+        libc::signal(libc::SIGQUIT, handle_shutdown_signal as libc::sighandler_t);
+    }
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,67 @@
+use std::io;
+use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ffi::CString;
+use std::mem;
+
+use libc;
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [libc::c_char; 16],
+    ifr_flags: libc::c_short,
+    _pad: [u8; 22]
+}
+
+pub struct TunDevice {
+    fd: File,
+    ifname: String
+}
+
+impl TunDevice {
+    pub fn new(ifname: &str) -> io::Result<Self> {
+        let fd = try!(OpenOptions::new().read(true).write(true).open("/dev/net/tun"));
+        let mut req: IfReq = unsafe { mem::zeroed() };
+        let name = CString::new(ifname).expect("Invalid interface name");
+        let name_bytes = name.as_bytes_with_nul();
+        for (i, &byte) in name_bytes.iter().enumerate().take(req.ifr_name.len()) {
+            req.ifr_name[i] = byte as libc::c_char;
+        }
+        req.ifr_flags = IFF_TUN | IFF_NO_PI;
+        let res = unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETIFF, &mut req) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let real_name = unsafe {
+            let len = req.ifr_name.iter().position(|&c| c == 0).unwrap_or(req.ifr_name.len());
+            String::from_utf8_lossy(&*(&req.ifr_name[..len] as *const _ as *const [u8])).into_owned()
+        };
+        Ok(TunDevice{fd: fd, ifname: real_name})
+    }
+
+    pub fn ifname(&self) -> &str {
+        &self.ifname
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+        self.fd.write_all(data)
+    }
+}
+
+impl Read for TunDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd.read(buf)
+    }
+}
+
+impl AsRawFd for TunDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}